@@ -1,63 +1,285 @@
 use std::fs::File;
+use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::io::Result as IOResult;
 use std::env;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
-const DEFAULT_HEADER: &'static str = r#"\documentclass[12pt, a4paper, twoside, titlepage]{article}
-\usepackage{amsmath}
-\usepackage{amsfonts}
-\usepackage{amssymb}
-\usepackage{a4}
-\usepackage[ngerman]{babel}
-\usepackage[utf8x]{inputenc}
-\usepackage{ragged2e}
-\begin{document}
-\begin{flushleft}
-"#;
+use flate2::read::MultiGzDecoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 const DEFAULT_FOOTER: &'static str = r#"\end{flushleft}
 \end{document}
 "#;
 
+/// Settings parsed from a document's frontmatter, used to build the LaTeX
+/// preamble. Falls back to the tool's historical defaults (German `babel`,
+/// `article` class) when a document has no frontmatter.
+pub struct DocumentConfig {
+    pub documentclass: String,
+    pub language: String,
+    pub fontsize: Option<String>,
+    pub packages: Vec<String>,
+    pub titlepage: bool
+}
+
+impl Default for DocumentConfig {
+    fn default() -> Self {
+        DocumentConfig {
+            documentclass: "article".to_owned(),
+            language: "ngerman".to_owned(),
+            fontsize: Some("12pt".to_owned()),
+            packages: vec![
+                "amsmath".to_owned(),
+                "amsfonts".to_owned(),
+                "amssymb".to_owned(),
+                "a4".to_owned(),
+                "ragged2e".to_owned()
+            ],
+            titlepage: true
+        }
+    }
+}
+
+impl DocumentConfig {
+    /// Parses `key: value` frontmatter lines into a `DocumentConfig`,
+    /// keeping the default for any key that is absent.
+    pub fn from_frontmatter(lines: &[String]) -> DocumentConfig {
+        let mut config = DocumentConfig::default();
+        let mut packages_set = false;
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, ':');
+            let key = match parts.next() {
+                Some(k) => k.trim(),
+                None => continue
+            };
+            let value = match parts.next() {
+                Some(v) => v.trim(),
+                None => continue
+            };
+            match key {
+                "documentclass" => config.documentclass = value.to_owned(),
+                "language" => config.language = value.to_owned(),
+                "fontsize" => config.fontsize = Some(value.to_owned()),
+                "titlepage" => config.titlepage = value == "true",
+                "packages" => {
+                    packages_set = true;
+                    config.packages = value
+                        .split(',')
+                        .map(|s| s.trim().to_owned())
+                        .filter(|s| !s.is_empty())
+                        .collect();
+                },
+                _ => {}
+            }
+        }
+        if !packages_set {
+            config.packages = DocumentConfig::default().packages;
+        }
+        config
+    }
+
+    /// Builds the `\documentclass`/`\usepackage` preamble described by this config.
+    pub fn build_header(&self) -> String {
+        let mut options = Vec::new();
+        if let Some(ref fontsize) = self.fontsize {
+            options.push(fontsize.clone());
+        }
+        options.push("a4paper".to_owned());
+        options.push("twoside".to_owned());
+        if self.titlepage {
+            options.push("titlepage".to_owned());
+        }
+        let mut buffer = String::new();
+        buffer.push_str(&format!("\\documentclass[{}]{{{}}}\n", options.join(", "), self.documentclass));
+        for package in &self.packages {
+            buffer.push_str(&format!("\\usepackage{{{}}}\n", package));
+        }
+        buffer.push_str(&format!("\\usepackage[{}]{{babel}}\n", self.language));
+        buffer.push_str("\\usepackage[utf8x]{inputenc}\n");
+        buffer.push_str("\\begin{document}\n");
+        buffer.push_str("\\begin{flushleft}\n");
+        buffer
+    }
+}
+
+/// Errors that can occur while reading, parsing or transpiling a document.
+#[derive(Debug)]
+pub enum PreprocError {
+    HeaderLevelTooDeep { level: usize, line: usize },
+    EmptyBlock,
+    Io(std::io::Error)
+}
+
+impl PreprocError {
+    /// The source line the error points at, if any.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            &PreprocError::HeaderLevelTooDeep { line, .. } => Some(line),
+            &PreprocError::EmptyBlock => None,
+            &PreprocError::Io(_) => None
+        }
+    }
+}
+
+impl fmt::Display for PreprocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PreprocError::HeaderLevelTooDeep { level, .. } => {
+                write!(f, "header level {} exceeds the maximum supported level of 5", level)
+            },
+            &PreprocError::EmptyBlock => write!(f, "encountered an empty block"),
+            &PreprocError::Io(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl From<std::io::Error> for PreprocError {
+    fn from(e: std::io::Error) -> Self {
+        PreprocError::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, PreprocError>;
+
 fn main() {
-    let args = env::args().skip(1).collect::<Vec<String>>();
+    let mut args = env::args().skip(1).collect::<Vec<String>>();
+    let watch = if let Some(pos) = args.iter().position(|a| a == "--watch") {
+        args.remove(pos);
+        true
+    }
+    else {
+        false
+    };
+
+    if watch {
+        match args.get(0) {
+            Some(filepath) => {
+                if let Err(e) = run_watch(filepath) {
+                    println!("{}: {}", filepath, e);
+                }
+            },
+            None => println!("--watch requires a file path")
+        }
+        return;
+    }
+
+    if args.is_empty() {
+        if let Err(e) = process_file("-") {
+            println!("-: {}", e);
+        }
+        return;
+    }
     for filepath in args {
-        let filecontent = match read_file(filepath.clone()) {
-            Ok(v) => v,
-            Err(e) => {
-                println!("error while reading {}: {}", filepath, e);
-                return;
+        if let Err(e) = process_file(&filepath) {
+            match e.line() {
+                Some(line) => println!("{}:{}: {}", filepath, line, e),
+                None => println!("{}: {}", filepath, e)
             }
-        };
-        let document = PreFile::from_string(filecontent);
-        let result = document.transpile();
-        match write_file(&format!("{}.tex", &filepath), result) {
-            Ok(_) => {},
-            Err(e) => {
-                println!("error while writing {}: {}", filepath, e);
+        }
+    }
+}
+
+/// Polls the modification time of `filepath` every 200ms and re-transpiles
+/// the file each time that time changes, reusing `BlockCache` so only blocks whose source changed
+/// are actually re-run.
+fn run_watch(filepath: &str) -> Result<()> {
+    let mut cache = BlockCache::new();
+    let mut last_modified = None;
+    loop {
+        let modified = std::fs::metadata(filepath)?.modified()?;
+        if Some(modified) != last_modified {
+            last_modified = Some(modified);
+            match rebuild(filepath, &mut cache) {
+                Ok(millis) => println!("rebuilt {}.tex in {}ms", filepath, millis),
+                Err(e) => match e.line() {
+                    Some(line) => println!("{}:{}: {}", filepath, line, e),
+                    None => println!("{}: {}", filepath, e)
+                }
             }
         }
+        thread::sleep(Duration::from_millis(200));
     }
 }
 
-fn read_file(path: String) -> IOResult<String> {
-    let mut file = File::open(path)?;
+fn rebuild(filepath: &str, cache: &mut BlockCache) -> Result<u128> {
+    let start = Instant::now();
+    let filecontent = read_file(filepath)?;
+    let document = PreFile::from_string(filecontent)?;
+    let result = document.transpile_with_cache(cache)?;
+    write_file(&format!("{}.tex", filepath), result)?;
+    Ok(start.elapsed().as_millis())
+}
+
+fn process_file(filepath: &str) -> Result<()> {
+    let filecontent = read_file(filepath)?;
+    let document = PreFile::from_string(filecontent)?;
+    let result = document.transpile()?;
+    if filepath == "-" {
+        write_file("-", result)?;
+    }
+    else {
+        write_file(&format!("{}.tex", filepath), result)?;
+    }
+    Ok(())
+}
+
+/// Opens `path` (or stdin, if `path` is `-`) and reads it fully, transparently
+/// decoding gzip input detected by its magic bytes.
+fn read_file(path: &str) -> IOResult<String> {
+    let raw = read_raw(path)?;
+    let mut reader: Box<dyn Read> = if raw.starts_with(&GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(&raw[..]))
+    }
+    else {
+        Box::new(&raw[..])
+    };
     let mut buffer = String::new();
-    let _ = file.read_to_string(&mut buffer)?;
+    reader.read_to_string(&mut buffer)?;
+    Ok(buffer)
+}
+
+fn read_raw(path: &str) -> IOResult<Vec<u8>> {
+    let mut reader: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    }
+    else {
+        Box::new(File::open(path)?)
+    };
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer)?;
     Ok(buffer)
 }
 
-fn write_file(path: &String, content: String) -> IOResult<()> {
-    let mut file = File::create(path)?;
-    file.write_all(content.as_bytes())?;
+/// Writes `content` to `path`, or to stdout if `path` is `-`.
+fn write_file(path: &str, content: String) -> IOResult<()> {
+    let mut writer: Box<dyn Write> = if path == "-" {
+        Box::new(io::stdout())
+    }
+    else {
+        Box::new(File::create(path)?)
+    };
+    writer.write_all(content.as_bytes())?;
     Ok(())
 }
 
 pub enum Line {
-    Normal(String),
-    Header(String, usize),
-    Align(String)
+    Normal(String, usize),
+    Header(String, usize, usize),
+    Align(String, usize),
+    Frontmatter(String, usize),
+    List(String, usize, bool, usize)
 }
 
 impl Line {
@@ -67,68 +289,140 @@ impl Line {
 
     pub fn get_type(&self) -> LineType {
         match self {
-            &Line::Normal(_) => LineType::Normal,
-            &Line::Header(_, x) => LineType::Header(x),
-            &Line::Align(_) => LineType::Align
+            &Line::Normal(_, _) => LineType::Normal,
+            &Line::Header(_, x, _) => LineType::Header(x),
+            &Line::Align(_, _) => LineType::Align,
+            &Line::Frontmatter(_, _) => LineType::Frontmatter,
+            &Line::List(_, _, _, _) => LineType::List
+        }
+    }
+
+    pub fn get_line_number(&self) -> usize {
+        match self {
+            &Line::Normal(_, n) => n,
+            &Line::Header(_, _, n) => n,
+            &Line::Align(_, n) => n,
+            &Line::Frontmatter(_, n) => n,
+            &Line::List(_, _, _, n) => n
+        }
+    }
+
+    pub fn get_indent(&self) -> usize {
+        match self {
+            &Line::List(_, indent, _, _) => indent,
+            _ => 0
+        }
+    }
+
+    pub fn get_ordered(&self) -> bool {
+        match self {
+            &Line::List(_, _, ordered, _) => ordered,
+            _ => false
         }
     }
 
     pub fn get_content(self) -> String {
         match self {
-            Line::Normal(s) => s,
-            Line::Header(s, _) => s,
-            Line::Align(s) => s
+            Line::Normal(s, _) => s,
+            Line::Header(s, _, _) => s,
+            Line::Align(s, _) => s,
+            Line::Frontmatter(s, _) => s,
+            Line::List(s, _, _, _) => s
         }
     }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Hash)]
 pub enum LineType {
     Normal,
     Header(usize),
-    Align
+    Align,
+    Frontmatter,
+    List
+}
+
+/// Caches the transpiled output of a `Block`, keyed by a hash of its source
+/// lines, so a watch-mode rebuild only re-transpiles blocks that changed.
+pub struct BlockCache {
+    entries: HashMap<u64, String>
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache { entries: HashMap::new() }
+    }
 }
 
 struct Block {
     block_type: LineType,
-    content: Vec<String>
+    content: Vec<String>,
+    indents: Vec<usize>,
+    list_ordered: Vec<bool>,
+    line: usize,
+    hash: u64
 }
 
 impl Block {
-    pub fn from_block_buffer(buffer: Vec<Line>) -> Block {
+    pub fn from_block_buffer(buffer: Vec<Line>) -> Result<Block> {
         if buffer.len() == 0 {
-            panic!("block buffer is empty");
+            return Err(PreprocError::EmptyBlock);
         }
         let block_type = buffer.get(0).unwrap().get_type();
+        let line = buffer.get(0).unwrap().get_line_number();
         let mut content_buffer = Vec::new();
+        let mut indents = Vec::new();
+        let mut list_ordered = Vec::new();
         for elem in buffer {
+            indents.push(elem.get_indent());
+            list_ordered.push(elem.get_ordered());
             content_buffer.push(elem.get_content());
         }
-        Block {
+        let mut hasher = DefaultHasher::new();
+        block_type.hash(&mut hasher);
+        content_buffer.hash(&mut hasher);
+        indents.hash(&mut hasher);
+        list_ordered.hash(&mut hasher);
+        let hash = hasher.finish();
+        Ok(Block {
             block_type: block_type,
-            content: content_buffer
+            content: content_buffer,
+            indents: indents,
+            list_ordered: list_ordered,
+            line: line,
+            hash: hash
+        })
+    }
+
+    /// Transpiles this block, reusing `cache` when its source lines are unchanged.
+    pub fn transpile_cached(self, cache: &mut BlockCache) -> Result<String> {
+        if let Some(cached) = cache.entries.get(&self.hash) {
+            return Ok(cached.clone());
         }
+        let hash = self.hash;
+        let result = self.transpile()?;
+        cache.entries.insert(hash, result.clone());
+        Ok(result)
     }
 
-    pub fn transpile(mut self) -> String {
+    pub fn transpile(mut self) -> Result<String> {
         match self.block_type {
             LineType::Normal => {
                 self.content = self.content
                     .into_iter()
                     .map(|x| if x.trim() == "~~" {format!("\\quad\\newline")} else {x})
                     .collect::<Vec<String>>();
-                format!("{}\n", fold_strings(self.content, "\n", "").trim())
+                Ok(format!("{}\n", fold_strings(self.content, "\n", "").trim()))
             },
             LineType::Header(n) => {
                 match n {
                     1 => {
-                        format!("\\section{{ {} }}\n", fold_strings(self.content, " ", "").trim())
+                        Ok(format!("\\section{{ {} }}\n", fold_strings(self.content, " ", "").trim()))
                     },
                     2 => {
-                        format!("\\subsection{{ {} }}\n", fold_strings(self.content, " ", "").trim())
+                        Ok(format!("\\subsection{{ {} }}\n", fold_strings(self.content, " ", "").trim()))
                     },
                     3 => {
-                        format!("\\subsubsection{{ {} }}\n", fold_strings(self.content, " ", "").trim())
+                        Ok(format!("\\subsubsection{{ {} }}\n", fold_strings(self.content, " ", "").trim()))
                     },
                     4 => {
                         let mut buffer = String::new();
@@ -140,13 +434,13 @@ impl Block {
                         buffer.push_str("\\normalsize\n");
                         buffer.push_str("\\endcenter\n");
                         buffer.push_str("\\begin{flushleft}\n");
-                        buffer
+                        Ok(buffer)
                     },
                     5 => {
-                        format!("\\textbf{{ {} }}\\\\\n", fold_strings(self.content, " ", "").trim())
+                        Ok(format!("\\textbf{{ {} }}\\\\\n", fold_strings(self.content, " ", "").trim()))
                     },
                     _ => {
-                        panic!("header level exceeded 2");
+                        Err(PreprocError::HeaderLevelTooDeep { level: n, line: self.line })
                     }
                 }
             },
@@ -169,34 +463,101 @@ impl Block {
                         }
                     }
                     buffer.push_str("\\\\\n");
-                }    
+                }
                 buffer.push_str("\\end{align*}\n");
-                buffer
+                Ok(buffer)
+            },
+            LineType::Frontmatter => Ok(String::new()),
+            LineType::List => {
+                fn env_name(ordered: bool) -> &'static str {
+                    if ordered { "enumerate" } else { "itemize" }
+                }
+                let mut buffer = String::new();
+                // Stack of (indent, environment) for the currently open nesting levels.
+                let mut stack: Vec<(usize, &'static str)> = Vec::new();
+                for (i, text) in self.content.iter().enumerate() {
+                    let indent = self.indents[i];
+                    let env = env_name(self.list_ordered[i]);
+                    while stack.last().map(|&(lvl, _)| indent < lvl).unwrap_or(false) {
+                        let (_, popped_env) = stack.pop().unwrap();
+                        buffer.push_str(&format!("\\end{{{}}}\n", popped_env));
+                    }
+                    if stack.last().map(|&(lvl, lvl_env)| lvl == indent && lvl_env != env).unwrap_or(false) {
+                        let (_, popped_env) = stack.pop().unwrap();
+                        buffer.push_str(&format!("\\end{{{}}}\n", popped_env));
+                    }
+                    if !stack.last().map(|&(lvl, _)| lvl == indent).unwrap_or(false) {
+                        buffer.push_str(&format!("\\begin{{{}}}\n", env));
+                        stack.push((indent, env));
+                    }
+                    buffer.push_str(&format!("\\item {}\n", text.trim()));
+                }
+                while let Some((_, popped_env)) = stack.pop() {
+                    buffer.push_str(&format!("\\end{{{}}}\n", popped_env));
+                }
+                Ok(buffer)
             }
         }
-    }   
+    }
 }
 
 pub fn fold_strings(string: Vec<String>, suffix: &'static str, prefix: &'static str) -> String {
     string.into_iter().fold(String::new(), (|mut acc, x| {
         acc.push_str(prefix);
         acc.push_str(&x);
-        acc.push_str(suffix); 
+        acc.push_str(suffix);
         acc
     }))
 }
 
+/// Recognizes a `- ` (unordered) or `N. ` (ordered) list marker, returning
+/// the item's content, its indentation (leading spaces before the marker,
+/// used for nesting), and whether it is ordered.
+fn parse_list_marker(line: &str) -> Option<(String, usize, bool)> {
+    let trimmed = line.trim_start();
+    let indent = line.len() - trimmed.len();
+    if trimmed.starts_with("- ") {
+        return Some((trimmed[2..].to_owned(), indent, false));
+    }
+    let digits = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 {
+        let rest = &trimmed[digits..];
+        if rest.starts_with(". ") {
+            return Some((rest[2..].to_owned(), indent, true));
+        }
+    }
+    None
+}
+
 pub struct PreFile {
-    blocks: Vec<Block>
+    blocks: Vec<Block>,
+    config: DocumentConfig
 }
 
 impl PreFile {
-    pub fn from_string(string: String) -> PreFile {
+    pub fn from_string(string: String) -> Result<PreFile> {
         let mut lines = Vec::new();
-        for line_str in string.lines() {
+        let mut in_frontmatter = false;
+        let mut frontmatter_lines = Vec::new();
+        for (line_number, line_str) in string.lines().enumerate() {
+            let line_number = line_number + 1;
             let line = line_str.to_owned();
+            if line_number == 1 && line.trim() == "---" {
+                in_frontmatter = true;
+                continue;
+            }
+            if in_frontmatter {
+                if line.trim() == "---" {
+                    in_frontmatter = false;
+                }
+                else {
+                    frontmatter_lines.push(line.clone());
+                    lines.push(Line::Frontmatter(line, line_number));
+                }
+                continue;
+            }
             if line.starts_with(">") {
-                lines.push(Line::Align(line[1..].to_owned()));
+                lines.push(Line::Align(line[1..].to_owned(), line_number));
             }
             else if line.starts_with("#") {
                 let mut counter = 0;
@@ -209,12 +570,21 @@ impl PreFile {
                     }
                 }
                 let cropped_line = line[counter..].to_owned();
-                lines.push(Line::Header(cropped_line, counter));
+                lines.push(Line::Header(cropped_line, counter, line_number));
             }
-            else { 
-                lines.push(Line::Normal(line));
+            else if let Some((content, indent, ordered)) = parse_list_marker(&line) {
+                lines.push(Line::List(content, indent, ordered, line_number));
             }
+            else {
+                lines.push(Line::Normal(line, line_number));
+            }
+        }
+        let config = if frontmatter_lines.is_empty() {
+            DocumentConfig::default()
         }
+        else {
+            DocumentConfig::from_frontmatter(&frontmatter_lines)
+        };
         let mut blocks = Vec::new();
         let mut block_buffer = Vec::new();
         let mut current_type = None;
@@ -227,25 +597,40 @@ impl PreFile {
                 block_buffer.push(line);
             }
             else {
-                blocks.push(Block::from_block_buffer(block_buffer));
+                blocks.push(Block::from_block_buffer(block_buffer)?);
                 block_buffer = Vec::new();
                 current_type = Some(line.get_type());
                 block_buffer.push(line);
             }
         }
-        blocks.push(Block::from_block_buffer(block_buffer));
-        PreFile {
-            blocks: blocks
+        if block_buffer.len() > 0 {
+            blocks.push(Block::from_block_buffer(block_buffer)?);
         }
+        Ok(PreFile {
+            blocks: blocks,
+            config: config
+        })
     }
 
-    pub fn transpile(self) -> String {
+    pub fn transpile(self) -> Result<String> {
         let mut buffer = String::new();
-        buffer.push_str(DEFAULT_HEADER);
+        buffer.push_str(&self.config.build_header());
         for elem in self.blocks {
-            buffer.push_str(&elem.transpile());
+            buffer.push_str(&elem.transpile()?);
         }
         buffer.push_str(&DEFAULT_FOOTER);
-        buffer
+        Ok(buffer)
+    }
+
+    /// Like `transpile`, but reuses `cache` so only blocks whose source
+    /// lines changed since the last call are re-run.
+    pub fn transpile_with_cache(self, cache: &mut BlockCache) -> Result<String> {
+        let mut buffer = String::new();
+        buffer.push_str(&self.config.build_header());
+        for elem in self.blocks {
+            buffer.push_str(&elem.transpile_cached(cache)?);
+        }
+        buffer.push_str(&DEFAULT_FOOTER);
+        Ok(buffer)
     }
 }